@@ -7,45 +7,235 @@ extern crate rocket;
 #[macro_use] extern crate serde_derive;
 extern crate serde_json;
 
+use std::collections::VecDeque;
+use std::env;
+use std::io::Cursor;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use libc::{c_double, c_int};
 
-use rocket_contrib::Json;
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket::State;
+use rocket_contrib::{Json, Template};
+
+/// Default interval, in seconds, between samples when
+/// `LOADAVG_SAMPLE_INTERVAL_SECS` isn't set.
+const DEFAULT_SAMPLE_INTERVAL_SECS: u64 = 10;
+
+/// How many samples `/loadavg/history` keeps before evicting the oldest.
+const HISTORY_CAPACITY: usize = 360;
 
 #[derive(Serialize)]
 struct LoadAvg {
     last: f64,
     last5: f64,
-    last15: f64
+    last15: f64,
+    runnable_tasks: u32,
+    total_tasks: u32,
+    last_pid: i32
 }
 
 extern {
-    fn getloadavg(load_avg: *mut c_double, load_avg_len: c_int);
+    fn getloadavg(load_avg: *mut c_double, load_avg_len: c_int) -> c_int;
 }
 
 
 impl LoadAvg {
-    fn new() -> LoadAvg {
-        let load_averages: [f64; 3] = unsafe {
-            let mut lavgs: [c_double; 3] = [0f64, 0f64, 0f64];
-            getloadavg(lavgs.as_mut_ptr(), 3);
-            lavgs
+    /// Calls `getloadavg(3)` and fails if fewer than three samples came
+    /// back, which can happen on a platform or system state where not
+    /// all of them are available; returning zeros in that case would be
+    /// misleading.
+    fn new() -> Result<LoadAvg, String> {
+        let mut lavgs: [c_double; 3] = [0f64, 0f64, 0f64];
+        let ret = unsafe { getloadavg(lavgs.as_mut_ptr(), 3) };
+
+        if ret != 3 {
+            return Err(format!("getloadavg returned {} samples, expected 3", ret));
+        }
+
+        Ok(LoadAvg {
+            last: lavgs[0],
+            last5: lavgs[1],
+            last15: lavgs[2],
+            runnable_tasks: 0,
+            total_tasks: 0,
+            last_pid: 0
+        })
+    }
+
+    /// Reads `/proc/loadavg`, which on Linux carries the runnable/total
+    /// scheduling entity counts and the last-created PID in addition to
+    /// the three load averages, e.g. `0.42 0.38 0.30 2/1234 56789`.
+    /// Falls back to `LoadAvg::new()` (and thus `getloadavg(3)`) when the
+    /// file is missing or malformed, which is always the case off Linux.
+    #[cfg(target_os = "linux")]
+    fn from_procfs() -> Result<LoadAvg, String> {
+        use std::fs;
+
+        let contents = match fs::read_to_string("/proc/loadavg") {
+            Ok(contents) => contents,
+            Err(_) => return LoadAvg::new()
         };
 
-        LoadAvg {
-            last: load_averages[0],
-            last5: load_averages[1],
-            last15: load_averages[2]
+        let mut fields = contents.split_whitespace();
+        let last = fields.next().and_then(|s| s.parse().ok());
+        let last5 = fields.next().and_then(|s| s.parse().ok());
+        let last15 = fields.next().and_then(|s| s.parse().ok());
+        let tasks = fields.next().and_then(|s| {
+            let mut parts = s.split('/');
+            let runnable = parts.next()?.parse().ok()?;
+            let total = parts.next()?.parse().ok()?;
+            Some((runnable, total))
+        });
+        let last_pid = fields.next().and_then(|s| s.parse().ok());
+
+        match (last, last5, last15, tasks, last_pid) {
+            (Some(last), Some(last5), Some(last15), Some((runnable_tasks, total_tasks)), Some(last_pid)) => Ok(LoadAvg {
+                last,
+                last5,
+                last15,
+                runnable_tasks,
+                total_tasks,
+                last_pid
+            }),
+            _ => LoadAvg::new()
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn from_procfs() -> Result<LoadAvg, String> {
+        LoadAvg::new()
+    }
+
+    /// Renders the three load averages as Prometheus text exposition
+    /// format, in the same vein as node_exporter's `node_load{1,5,15}`
+    /// gauges.
+    fn to_prometheus(&self) -> String {
+        format!(
+            "# HELP node_load1 1m load average.\n\
+             # TYPE node_load1 gauge\n\
+             node_load1 {}\n\
+             # HELP node_load5 5m load average.\n\
+             # TYPE node_load5 gauge\n\
+             node_load5 {}\n\
+             # HELP node_load15 15m load average.\n\
+             # TYPE node_load15 gauge\n\
+             node_load15 {}\n",
+            self.last, self.last5, self.last15
+        )
+    }
+}
+
+/// A single historical reading, recorded by the background sampler.
+#[derive(Serialize, Clone)]
+struct Sample {
+    timestamp: u64,
+    last: f64,
+    last5: f64,
+    last15: f64
+}
+
+impl Sample {
+    fn now(avg: &LoadAvg) -> Sample {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        Sample {
+            timestamp,
+            last: avg.last,
+            last5: avg.last5,
+            last15: avg.last15
+        }
+    }
+}
+
+/// Rocket-managed handle to the bounded ring buffer of `Sample`s; shared
+/// with the background sampler thread spawned in `main()`.
+type SampleStore = Arc<RwLock<VecDeque<Sample>>>;
+
+/// Reads the sample interval from `LOADAVG_SAMPLE_INTERVAL_SECS`, falling
+/// back to `DEFAULT_SAMPLE_INTERVAL_SECS` when unset or unparseable.
+/// Clamped to at least 1 second so a misconfigured `0` can't turn the
+/// sampler into a busy-loop that pegs a core and thrashes the `RwLock`.
+fn sample_interval() -> Duration {
+    let secs = env::var("LOADAVG_SAMPLE_INTERVAL_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_SAMPLE_INTERVAL_SECS)
+        .max(1);
+
+    Duration::from_secs(secs)
+}
+
+/// Spawns a thread that samples `LoadAvg::from_procfs()` on `interval`
+/// and pushes the result onto `store`, evicting the oldest sample once
+/// `HISTORY_CAPACITY` is reached.
+fn spawn_sampler(store: SampleStore, interval: Duration) {
+    thread::spawn(move || loop {
+        if let Ok(avg) = LoadAvg::from_procfs() {
+            let mut samples = store.write().unwrap_or_else(|e| e.into_inner());
+            if samples.len() >= HISTORY_CAPACITY {
+                samples.pop_front();
+            }
+            samples.push_back(Sample::now(&avg));
         }
+
+        thread::sleep(interval);
+    });
+}
+
+/// A plain-text Prometheus exposition body, distinct from `Json` so the
+/// response carries `text/plain; version=0.0.4` instead of
+/// `application/json`.
+struct Metrics(String);
+
+impl<'r> Responder<'r> for Metrics {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(ContentType::new("text", "plain").with_params(("version", "0.0.4")))
+            .sized_body(Cursor::new(self.0))
+            .ok()
     }
 }
 
-#[get("/loadavg")]
-fn loadavg() -> Json<LoadAvg> {
-    Json(LoadAvg::new())
+#[get("/loadavg", format = "application/json")]
+fn loadavg_json() -> Result<Json<LoadAvg>, (Status, String)> {
+    LoadAvg::from_procfs().map(Json).map_err(|err| (Status::ServiceUnavailable, err))
+}
+
+#[get("/loadavg", format = "text/html")]
+fn loadavg_html() -> Result<Template, (Status, String)> {
+    LoadAvg::from_procfs()
+        .map(|avg| Template::render("loadavg", avg))
+        .map_err(|err| (Status::ServiceUnavailable, err))
+}
+
+#[get("/metrics")]
+fn metrics() -> Result<Metrics, (Status, String)> {
+    LoadAvg::from_procfs()
+        .map(|avg| Metrics(avg.to_prometheus()))
+        .map_err(|err| (Status::ServiceUnavailable, err))
+}
+
+#[get("/loadavg/history")]
+fn loadavg_history(store: State<SampleStore>) -> Json<Vec<Sample>> {
+    let samples = store.read().unwrap_or_else(|e| e.into_inner());
+    Json(samples.iter().cloned().collect())
 }
 
 fn main() {
+    let store: SampleStore = Arc::new(RwLock::new(VecDeque::with_capacity(HISTORY_CAPACITY)));
+    spawn_sampler(store.clone(), sample_interval());
+
     rocket::ignite()
-        .mount("/", routes![loadavg])
+        .manage(store)
+        .mount("/", routes![loadavg_json, loadavg_html, metrics, loadavg_history])
+        .attach(Template::fairing())
         .launch();
 }